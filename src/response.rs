@@ -1,6 +1,8 @@
 //! The APNs response types
 
+use serde::{Deserialize, Deserializer};
 use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// The response data from APNs.
 #[derive(Debug)]
@@ -27,22 +29,52 @@ pub struct Response {
 }
 
 /// The response body from APNs. Only available for errors.
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct ErrorBody {
     /// The error indicating the reason for the failure.
     pub reason: ErrorReason,
 
-    /// If the value of the `ErrorReason` is `Unregistered`, the value of this
-    /// key is the last time at which APNs confirmed that the device token was
-    /// no longer valid for the topic.
+    /// If the value of the `ErrorReason` is `Unregistered` or `ExpiredToken`,
+    /// the value of this key is the last time at which APNs confirmed that
+    /// the device token was no longer valid for the topic.
     ///
     /// Stop pushing notifications until the device registers a token with a
     /// later timestamp with your provider.
     pub timestamp: Option<u64>,
 }
 
+impl ErrorBody {
+    /// The time APNs confirmed the device token was no longer valid, parsed
+    /// from `timestamp`.
+    ///
+    /// Only set when `reason` is `Unregistered` or `ExpiredToken`. If you
+    /// hold a token whose own registration timestamp is at or before this
+    /// value, stop sending to it and purge it; if the device re-registered
+    /// with a newer token afterwards, you may keep sending to that one.
+    pub fn invalidated_token_since(&self) -> Option<SystemTime> {
+        match self.reason {
+            ErrorReason::Unregistered | ErrorReason::ExpiredToken => self
+                .timestamp
+                .map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+
+    /// `true` if APNs gave a timestamp-bounded invalidation window for this
+    /// token (`Unregistered`/`ExpiredToken`): purge any stored token with a
+    /// registration time at or before [`invalidated_token_since`](Self::invalidated_token_since).
+    ///
+    /// Other reasons in the `TokenInvalid` [`category`](ErrorReason::category)
+    /// (e.g. `BadDeviceToken`) are also never retryable, but carry no
+    /// timestamp — check `self.reason.category() == ErrorCategory::TokenInvalid`
+    /// for those, since there's no "newer token" to compare against.
+    pub fn should_delete_token(&self) -> bool {
+        self.invalidated_token_since().is_some()
+    }
+}
+
 /// A description what went wrong with the push notification.
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ErrorReason {
     /// The collapse identifier exceeds the maximum allowed size.
     BadCollapseId,
@@ -133,11 +165,262 @@ pub enum ErrorReason {
 
     /// The server is shutting down.
     Shutdown,
+
+    /// The device token has expired. You should stop sending notifications
+    /// to this token.
+    ExpiredToken,
+
+    /// A reason APNs sent that this version of the library doesn't know
+    /// about yet. Holds the original string so it can still be logged.
+    Unknown(String),
+}
+
+impl<'de> Deserialize<'de> for ErrorReason {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+
+        let reason = match s.as_str() {
+            "BadCollapseId" => ErrorReason::BadCollapseId,
+            "BadDeviceToken" => ErrorReason::BadDeviceToken,
+            "BadExpirationDate" => ErrorReason::BadExpirationDate,
+            "BadMessageId" => ErrorReason::BadMessageId,
+            "BadPriority" => ErrorReason::BadPriority,
+            "BadTopic" => ErrorReason::BadTopic,
+            "DeviceTokenNotForTopic" => ErrorReason::DeviceTokenNotForTopic,
+            "DuplicateHeaders" => ErrorReason::DuplicateHeaders,
+            "IdleTimeout" => ErrorReason::IdleTimeout,
+            "MissingDeviceToken" => ErrorReason::MissingDeviceToken,
+            "MissingTopic" => ErrorReason::MissingTopic,
+            "PayloadEmpty" => ErrorReason::PayloadEmpty,
+            "TopicDisallowed" => ErrorReason::TopicDisallowed,
+            "BadCertificate" => ErrorReason::BadCertificate,
+            "BadCertificateEnvironment" => ErrorReason::BadCertificateEnvironment,
+            "ExpiredProviderToken" => ErrorReason::ExpiredProviderToken,
+            "Forbidden" => ErrorReason::Forbidden,
+            "InvalidProviderToken" => ErrorReason::InvalidProviderToken,
+            "MissingProviderToken" => ErrorReason::MissingProviderToken,
+            "BadPath" => ErrorReason::BadPath,
+            "MethodNotAllowed" => ErrorReason::MethodNotAllowed,
+            "Unregistered" => ErrorReason::Unregistered,
+            "PayloadTooLarge" => ErrorReason::PayloadTooLarge,
+            "TooManyProviderTokenUpdates" => ErrorReason::TooManyProviderTokenUpdates,
+            "TooManyRequests" => ErrorReason::TooManyRequests,
+            "InternalServerError" => ErrorReason::InternalServerError,
+            "ServiceUnavailable" => ErrorReason::ServiceUnavailable,
+            "Shutdown" => ErrorReason::Shutdown,
+            "ExpiredToken" => ErrorReason::ExpiredToken,
+            _ => ErrorReason::Unknown(s),
+        };
+
+        Ok(reason)
+    }
+}
+
+/// A coarse-grained grouping of `ErrorReason`s, describing how a sender
+/// should react to the failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The failure is transient. Back off and send the notification again
+    /// later.
+    Retryable,
+
+    /// The device token will never work again. Stop sending notifications
+    /// to it and remove it from storage.
+    TokenInvalid,
+
+    /// The provider credentials are missing, invalid or stale. Refresh them
+    /// before retrying.
+    AuthError,
+
+    /// The request itself is malformed. Retrying without changing the
+    /// payload or headers will fail again.
+    Fatal,
+}
+
+impl ErrorReason {
+    /// Groups this reason into a category, so callers can decide how to
+    /// react without matching on every individual variant.
+    pub fn category(&self) -> ErrorCategory {
+        match *self {
+            ErrorReason::TooManyRequests
+            | ErrorReason::TooManyProviderTokenUpdates
+            | ErrorReason::InternalServerError
+            | ErrorReason::ServiceUnavailable
+            | ErrorReason::Shutdown
+            | ErrorReason::IdleTimeout => ErrorCategory::Retryable,
+
+            ErrorReason::Unregistered
+            | ErrorReason::ExpiredToken
+            | ErrorReason::BadDeviceToken
+            | ErrorReason::DeviceTokenNotForTopic
+            | ErrorReason::MissingDeviceToken => ErrorCategory::TokenInvalid,
+
+            ErrorReason::ExpiredProviderToken
+            | ErrorReason::InvalidProviderToken
+            | ErrorReason::MissingProviderToken
+            | ErrorReason::BadCertificate
+            | ErrorReason::BadCertificateEnvironment => ErrorCategory::AuthError,
+
+            _ => ErrorCategory::Fatal,
+        }
+    }
+
+    /// `true` for the one auth error that's worth a single retry:
+    /// `ExpiredProviderToken` means the provider token is stale, not
+    /// invalid. Regenerate it and send the notification once more.
+    ///
+    /// Other `AuthError` reasons (bad/missing credentials) won't succeed on
+    /// retry no matter how many times the token is regenerated.
+    pub fn should_regenerate_token_and_retry(&self) -> bool {
+        *self == ErrorReason::ExpiredProviderToken
+    }
+}
+
+impl Response {
+    /// `true` if the failure is transient and the notification can be sent
+    /// again, optionally after some backoff.
+    ///
+    /// Decided from the `category` of the error body's reason. Returns
+    /// `false` for a successful response, since there's nothing to retry,
+    /// and for any response without an error body, whatever its `code`.
+    pub fn is_retryable(&self) -> bool {
+        self.error
+            .as_ref()
+            .map(|error| error.reason.category() == ErrorCategory::Retryable)
+            .unwrap_or(false)
+    }
+
+    /// Converts the response into a `Result`, so callers can use `?` instead
+    /// of matching on `code` and `error` by hand.
+    ///
+    /// Returns `Ok` if `code` is 200, otherwise an `Err` carrying the
+    /// `ErrorReason` (when APNs sent a decodable body) together with the
+    /// HTTP `code`, `apns_id` and `timestamp`.
+    pub fn into_result(self) -> Result<Response, ResponseError> {
+        if self.code == 200 {
+            return Ok(self);
+        }
+
+        let Response {
+            error,
+            apns_id,
+            code,
+        } = self;
+
+        let (reason, timestamp) = match error {
+            Some(body) => (Some(body.reason), body.timestamp),
+            None => (None, None),
+        };
+
+        Err(ResponseError {
+            reason,
+            code,
+            apns_id,
+            timestamp,
+        })
+    }
+
+    /// The borrowing equivalent of [`into_result`](Response::into_result),
+    /// for when the `Response` is still needed afterwards.
+    pub fn as_result(&self) -> Result<&Response, ResponseError> {
+        if self.code == 200 {
+            return Ok(self);
+        }
+
+        Err(ResponseError {
+            reason: self.error.as_ref().map(|body| body.reason.clone()),
+            code: self.code,
+            apns_id: self.apns_id.clone(),
+            timestamp: self.error.as_ref().and_then(|body| body.timestamp),
+        })
+    }
+
+    /// The time APNs confirmed the device token was no longer valid. See
+    /// [`ErrorBody::invalidated_token_since`].
+    pub fn invalidated_token_since(&self) -> Option<SystemTime> {
+        self.error
+            .as_ref()
+            .and_then(ErrorBody::invalidated_token_since)
+    }
+
+    /// `true` if APNs gave a timestamp-bounded invalidation for the device
+    /// token (`Unregistered`/`ExpiredToken` with a `timestamp`). See
+    /// [`ErrorBody::should_delete_token`]; other `TokenInvalid` reasons such
+    /// as `BadDeviceToken` return `false`.
+    pub fn should_delete_token(&self) -> bool {
+        self.invalidated_token_since().is_some()
+    }
+}
+
+/// An error carrying everything APNs told us about a failed notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseError {
+    /// The reason the notification was rejected, if APNs sent one. `None`
+    /// when the response body couldn't be decoded into an `ErrorBody` at
+    /// all, e.g. a non-200 response from an intermediary proxy with no
+    /// JSON body.
+    pub reason: Option<ErrorReason>,
+
+    /// The HTTP response code.
+    pub code: u16,
+
+    /// Is the value defined in the `NotificationOptions` or a new Uuid
+    /// generated by APNs.
+    pub apns_id: Option<String>,
+
+    /// If the value of the `reason` is `Unregistered` or `ExpiredToken`, the
+    /// last time at which APNs confirmed that the device token was no
+    /// longer valid for the topic.
+    pub timestamp: Option<u64>,
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Some(ref reason) => write!(
+                f,
+                "APNs rejected the notification ({}): {}",
+                self.code, reason
+            ),
+            None => write!(
+                f,
+                "APNs rejected the notification ({}) with no decodable error body",
+                self.code
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.reason
+            .as_ref()
+            .map(|reason| reason as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.reason.fmt(f)
+    }
+}
+
+impl std::error::Error for ErrorBody {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.reason)
+    }
 }
 
 impl fmt::Display for ErrorReason {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = match *self {
+        if let ErrorReason::Unknown(ref reason) = self {
+            return write!(f, "Unknown APNs error reason: {}", reason);
+        }
+
+        let s = match self {
             ErrorReason::BadCollapseId =>
                 "The collapse identifier exceeds the maximum allowed size.",
             ErrorReason::BadDeviceToken =>
@@ -194,12 +477,17 @@ impl fmt::Display for ErrorReason {
                 "The service is unavailable.",
             ErrorReason::Shutdown =>
                 "The server is shutting down.",
+            ErrorReason::ExpiredToken =>
+                "The device token has expired. You should stop sending notifications to this token.",
+            ErrorReason::Unknown(_) => unreachable!("returned above"),
         };
 
         f.write_str(s)
     }
 }
 
+impl std::error::Error for ErrorReason {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -268,6 +556,16 @@ mod tests {
             ),
             (ErrorReason::ServiceUnavailable, "ServiceUnavailable", None),
             (ErrorReason::Shutdown, "Shutdown", None),
+            (
+                ErrorReason::ExpiredToken,
+                "ExpiredToken",
+                Some(1508249865488u64),
+            ),
+            (
+                ErrorReason::Unknown(String::from("SomeFutureReason")),
+                "SomeFutureReason",
+                None,
+            ),
         ];
 
         for error in errors.into_iter() {
@@ -294,4 +592,67 @@ mod tests {
             assert_eq!(expected_body, response_body);
         }
     }
+
+    #[test]
+    fn test_into_result_with_undecodable_body() {
+        let response = Response {
+            error: None,
+            apns_id: None,
+            code: 503,
+        };
+
+        let err = response.into_result().unwrap_err();
+
+        assert_eq!(None, err.reason);
+        assert_eq!(503, err.code);
+    }
+
+    #[test]
+    fn test_as_result_with_decodable_body() {
+        let response = Response {
+            error: Some(ErrorBody {
+                reason: ErrorReason::Unregistered,
+                timestamp: Some(1508249865488u64),
+            }),
+            apns_id: Some(String::from("abc-123")),
+            code: 410,
+        };
+
+        let err = response.as_result().unwrap_err();
+
+        assert_eq!(Some(ErrorReason::Unregistered), err.reason);
+        assert_eq!(Some(1508249865488u64), err.timestamp);
+        assert_eq!(Some(String::from("abc-123")), err.apns_id);
+    }
+
+    #[test]
+    fn test_should_regenerate_token_and_retry() {
+        assert!(ErrorReason::ExpiredProviderToken.should_regenerate_token_and_retry());
+
+        assert!(!ErrorReason::InvalidProviderToken.should_regenerate_token_and_retry());
+        assert!(!ErrorReason::BadCertificate.should_regenerate_token_and_retry());
+        assert!(!ErrorReason::TooManyRequests.should_regenerate_token_and_retry());
+    }
+
+    #[test]
+    fn test_invalidated_token_since() {
+        let unregistered = ErrorBody {
+            reason: ErrorReason::Unregistered,
+            timestamp: Some(1508249865488u64),
+        };
+
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_millis(1508249865488u64)),
+            unregistered.invalidated_token_since()
+        );
+        assert!(unregistered.should_delete_token());
+
+        let bad_device_token = ErrorBody {
+            reason: ErrorReason::BadDeviceToken,
+            timestamp: None,
+        };
+
+        assert_eq!(None, bad_device_token.invalidated_token_since());
+        assert!(!bad_device_token.should_delete_token());
+    }
 }
\ No newline at end of file